@@ -2,20 +2,189 @@ use std::cmp::Ordering;
 
 use crate::{Block, Document, FormattedLine, LineType, RawLine};
 
+/// Output/detected indentation unit: literal tabs, or a fixed number of spaces per level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndentStyle {
+    Tabs,
+    Spaces(u8),
+}
+
+/// Renders `level` indent levels as literal output text under `style`.
+pub fn render_indent(level: usize, style: IndentStyle) -> String {
+    match style {
+        IndentStyle::Tabs => "\t".repeat(level),
+        IndentStyle::Spaces(width) => " ".repeat(level * width as usize),
+    }
+}
+
+impl FormattedLine {
+    /// Renders this line's `indent_level` under `style` followed by its `contents`.
+    pub fn render(&self, style: IndentStyle) -> String {
+        format!(
+            "{}{}",
+            render_indent(self.indent_level, style),
+            self.contents
+        )
+    }
+}
+
+// Space widths considered when guessing how a document was indented, widest first so ties
+// favor the larger width.
+const CANDIDATE_SPACE_WIDTHS: [u8; 3] = [8, 4, 2];
+
+const DEFAULT_TAB_WIDTH: u8 = 4;
+
 enum Context {
     Normal,
-    HandlingFencedFiletype { base_indent: usize },
+    HandlingFencedFiletype {
+        filetype: Option<String>,
+        buffered_lines: Vec<(String, RawLine)>,
+    },
+}
+
+/// A pluggable source of re-indented code for a fenced block, keyed by filetype. Blocks whose
+/// filetype has no registered indenter, or whose indenter declines, fall back to the plain dedent.
+pub trait FencedCodeIndenter {
+    /// The fenced block filetype this indenter handles, as declared by the fence marker.
+    fn filetype(&self) -> &str;
+
+    /// Re-indents `code`, one line per input line, or `None` to fall back to the plain dedent.
+    fn reindent(&self, code: &str) -> Option<Vec<String>>;
+}
+
+fn find_indenter<'a>(
+    indenters: &'a [&'a dyn FencedCodeIndenter],
+    filetype: &str,
+) -> Option<&'a dyn FencedCodeIndenter> {
+    indenters
+        .iter()
+        .find(|indenter| indenter.filetype() == filetype)
+        .copied()
+}
+
+/// Extracts the filetype a fence marker declares, e.g. `rust` from ```` ```rust,no_run ````.
+fn marker_filetype(raw_line: &RawLine) -> Option<String> {
+    let filetype = raw_line
+        .trimmed
+        .trim_start_matches(|ch: char| !ch.is_alphanumeric())
+        .split(|ch: char| ch.is_whitespace() || ch == ',')
+        .next()
+        .unwrap_or("");
+
+    if filetype.is_empty() {
+        None
+    } else {
+        Some(filetype.to_string())
+    }
+}
+
+// A tree-sitter-backed FencedCodeIndenter was drafted here, but this repo has no Cargo manifest
+// to declare tree-sitter as a dependency, so it would never compile in. Implement it as a
+// separate crate once that manifest exists, and register it like any other indenter.
+
+/// Guesses the document's indentation convention (similar to Helix's `auto_detect_indent_style`)
+/// by histogramming how much leading whitespace grows between consecutive non-blank lines. Tabs
+/// are always measured as `DEFAULT_TAB_WIDTH` columns; this function has no way to infer how wide
+/// a tab is actually meant to render, so it doesn't pretend to detect one.
+fn detect_indent_style(contents: &str) -> IndentStyle {
+    let mut space_votes = [0usize; CANDIDATE_SPACE_WIDTHS.len()];
+    let mut tab_votes = 0usize;
+    let mut previous_width: Option<usize> = None;
+
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if line.starts_with('\t') {
+            tab_votes += 1;
+        }
+
+        let width = leading_whitespace_width(line, DEFAULT_TAB_WIDTH);
+
+        if let Some(prev_width) = previous_width {
+            let grew_by = width.saturating_sub(prev_width);
+
+            if let Some(bucket) = CANDIDATE_SPACE_WIDTHS
+                .iter()
+                .position(|&candidate| grew_by == candidate as usize)
+            {
+                space_votes[bucket] += 1;
+            }
+        }
+
+        previous_width = Some(width);
+    }
+
+    let mut best_bucket = 0;
+    let mut best_votes = space_votes[0];
+
+    for (bucket, &votes) in space_votes.iter().enumerate().skip(1) {
+        if votes > best_votes {
+            best_votes = votes;
+            best_bucket = bucket;
+        }
+    }
+
+    if tab_votes > best_votes {
+        IndentStyle::Tabs
+    } else if best_votes == 0 {
+        IndentStyle::Spaces(4)
+    } else {
+        IndentStyle::Spaces(CANDIDATE_SPACE_WIDTHS[best_bucket])
+    }
+}
+
+/// Computes the column width of `line`'s leading whitespace, expanding tabs to `tab_width` each.
+fn leading_whitespace_width(line: &str, tab_width: u8) -> usize {
+    let mut width = 0;
+
+    for ch in line.chars() {
+        match ch {
+            ' ' => width += 1,
+            '\t' => width += tab_width as usize,
+            _ => break,
+        }
+    }
+
+    width
+}
+
+/// Converts a raw leading-whitespace column width into a logical indent level under `style`.
+fn logical_indent_level(width: usize, style: IndentStyle, tab_width: u8) -> usize {
+    let unit = match style {
+        IndentStyle::Tabs => tab_width as usize,
+        IndentStyle::Spaces(width) => width as usize,
+    };
+
+    width / unit.max(1)
 }
 
 /// Parses the lines of `contents` and determines the type of line (header, bullet point list,
 /// etc.) and decides the indenting each line needs to get.
 pub fn parse_document(contents: &str) -> Document {
+    parse_document_with_indenters(contents, &[])
+}
+
+/// Same as [`parse_document`], but re-indents fenced code blocks via a matching `indenters` entry
+/// instead of the plain common-minimum-indentation dedent.
+pub fn parse_document_with_indenters(
+    contents: &str,
+    indenters: &[&dyn FencedCodeIndenter],
+) -> Document {
     let mut document = Document::new();
 
     let mut context = Context::Normal;
+    let indent_style = detect_indent_style(contents);
+    let tab_width = DEFAULT_TAB_WIDTH;
 
     for line in contents.lines() {
-        let raw_line = RawLine::from_string(line);
+        let mut raw_line = RawLine::from_string(line);
+        raw_line.num_indent = logical_indent_level(
+            leading_whitespace_width(line, tab_width),
+            indent_style,
+            tab_width,
+        );
 
         if raw_line.is_header() {
             // Finding a header means the start of a new Block
@@ -37,12 +206,33 @@ pub fn parse_document(contents: &str) -> Document {
             // A marker for a fenced filetype was encountered. Until the marker is repeated all
             // lines after this one should be considered to be preformatted.
 
-            context = match context {
-                Context::Normal => Context::HandlingFencedFiletype {
-                    base_indent: raw_line.num_indent,
-                },
-                Context::HandlingFencedFiletype { .. } => Context::Normal,
-            };
+            match context {
+                Context::Normal => {
+                    context = Context::HandlingFencedFiletype {
+                        filetype: marker_filetype(&raw_line),
+                        buffered_lines: Vec::new(),
+                    };
+                }
+                Context::HandlingFencedFiletype {
+                    filetype,
+                    buffered_lines,
+                } => {
+                    let current_block = document.last_block_mut();
+                    let block_indent_level = current_block.contents_indent_level();
+
+                    for preformatted_line in finalize_fenced_block(
+                        buffered_lines,
+                        filetype,
+                        indenters,
+                        tab_width,
+                        block_indent_level,
+                    ) {
+                        current_block.add_line(preformatted_line);
+                    }
+
+                    context = Context::Normal;
+                }
+            }
 
             let current_block = document.last_block_mut();
             let line = parse_text_line(current_block, raw_line);
@@ -52,35 +242,131 @@ pub fn parse_document(contents: &str) -> Document {
             // In this case the line is either a normal line of text, some prefixed line (like a
             // quote or preformatted) or the continuation of a (line wrapped) bullet point.
 
-            let current_block = document.last_block_mut();
-
-            let line = if let Context::HandlingFencedFiletype { base_indent } = context {
-                // This is a line that is part of a preformatted range of text (e.g. code)
-                //
-                // Preserve the existing indenting of the text/code in these lines that would
-                // otherwise be trimmed off.
-                FormattedLine {
-                    indent_level: current_block.contents_indent_level(),
-                    line_type: LineType::Preformatted,
-                    contents: format!(
-                        "{preformat_indent}{text}",
-                        preformat_indent =
-                            " ".repeat(raw_line.num_indent.saturating_sub(base_indent)),
-                        text = &raw_line.trimmed
-                    ),
-                    original_raw: raw_line,
+            match &mut context {
+                Context::HandlingFencedFiletype { buffered_lines, .. } => {
+                    // Buffer this line of the preformatted range of text (e.g. code) until the
+                    // closing marker is found, so the whole block's common indentation can be
+                    // stripped at once.
+                    buffered_lines.push((line.to_string(), raw_line));
                 }
-            } else {
-                parse_text_line(current_block, raw_line)
-            };
+                Context::Normal => {
+                    let current_block = document.last_block_mut();
+                    let line = parse_text_line(current_block, raw_line);
 
-            current_block.add_line(line);
+                    current_block.add_line(line);
+                }
+            }
         };
     }
 
+    if let Context::HandlingFencedFiletype {
+        filetype,
+        buffered_lines,
+    } = context
+    {
+        // The fence was never closed; flush what was buffered rather than silently dropping it.
+        let current_block = document.last_block_mut();
+        let block_indent_level = current_block.contents_indent_level();
+
+        for preformatted_line in finalize_fenced_block(
+            buffered_lines,
+            filetype,
+            indenters,
+            tab_width,
+            block_indent_level,
+        ) {
+            current_block.add_line(preformatted_line);
+        }
+    }
+
     document
 }
 
+/// Re-indents a completed fenced block with a matching `FencedCodeIndenter` if one is available
+/// and willing, or falls back to the plain common-minimum-indentation dedent.
+fn finalize_fenced_block(
+    buffered_lines: Vec<(String, RawLine)>,
+    filetype: Option<String>,
+    indenters: &[&dyn FencedCodeIndenter],
+    tab_width: u8,
+    indent_level: usize,
+) -> Vec<FormattedLine> {
+    if let Some(indenter) = filetype
+        .as_deref()
+        .and_then(|ft| find_indenter(indenters, ft))
+    {
+        let code = buffered_lines
+            .iter()
+            .map(|(original, _)| original.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if let Some(reindented_lines) = indenter.reindent(&code) {
+            if reindented_lines.len() == buffered_lines.len() {
+                return buffered_lines
+                    .into_iter()
+                    .zip(reindented_lines)
+                    .map(|((_, raw_line), contents)| FormattedLine {
+                        indent_level,
+                        line_type: LineType::Preformatted,
+                        contents,
+                        original_raw: raw_line,
+                    })
+                    .collect();
+            }
+            // A conforming indenter returns one line per input line; a mismatch means it's
+            // buggy or only partially handled the block, so fall back rather than risk silently
+            // dropping or misaligning lines via `zip`.
+        }
+    }
+
+    dedent_fenced_block(buffered_lines, tab_width, indent_level)
+}
+
+/// Dedents a buffered fenced block by stripping the common minimum leading indentation shared by
+/// its non-blank lines (the first line's indentation is ignored if zero), leaving deeper relative
+/// indentation intact.
+fn dedent_fenced_block(
+    buffered_lines: Vec<(String, RawLine)>,
+    tab_width: u8,
+    indent_level: usize,
+) -> Vec<FormattedLine> {
+    let min_indent = buffered_lines
+        .iter()
+        .enumerate()
+        .filter(|(index, (original, raw_line))| {
+            if raw_line.is_empty() {
+                return false;
+            }
+
+            *index != 0 || leading_whitespace_width(original, tab_width) != 0
+        })
+        .map(|(_, (original, _))| leading_whitespace_width(original, tab_width))
+        .min()
+        .unwrap_or(0);
+
+    buffered_lines
+        .into_iter()
+        .map(|(original, raw_line)| {
+            let contents = if raw_line.is_empty() {
+                String::new()
+            } else {
+                let width = leading_whitespace_width(&original, tab_width);
+                let dedented = width.saturating_sub(min_indent);
+
+                format!("{}{}", " ".repeat(dedented), raw_line.trimmed)
+            };
+
+            FormattedLine {
+                indent_level,
+                line_type: LineType::Preformatted,
+                contents,
+                original_raw: raw_line,
+            }
+        })
+        .collect()
+}
+
 /// Determines if the given line is a child, sibling or parent of the previous block's header
 fn determine_new_header_indent(document: &Document, raw_line: &RawLine) -> usize {
     assert!(raw_line.is_header());
@@ -136,6 +422,11 @@ fn determine_new_bullet_point_indent(current_block: &Block, raw_line: &RawLine)
         }
     } else if let Some(previous_text) = current_block.find_previous_of(LineType::Text) {
         previous_text.indent_level
+    } else if let Some(last_line) = current_block.last_line() {
+        // No previous list item or text line exists in this block yet (e.g. the list starts
+        // right after some other kind of line). Copy the immediately preceding line's already
+        // computed render depth instead of snapping back to the block's base indent.
+        last_line.indent_level
     } else {
         current_block.contents_indent_level()
     }
@@ -154,14 +445,11 @@ fn parse_text_line(current_block: &mut Block, raw_line: RawLine) -> FormattedLin
                 ),
                 original_raw: raw_line,
             }
-        } else if current_block.has_header() {
-            // Non-bullet list Contents of a block follow the block's indent level plus one
-            FormattedLine::from_raw(raw_line, current_block.contents_indent_level())
         } else {
-            // This applies to empty lines and to lines of text that are placed before the
-            // very first header of the document.
-
-            FormattedLine::from_raw(raw_line, current_block.contents_indent_level())
+            // Wrapped text, or an orphaned continuation with no preceding list item to align
+            // under: copy the immediately preceding line's already computed render depth rather
+            // than snapping back to the block's base indent.
+            FormattedLine::from_raw(raw_line, previous_line.indent_level)
         }
     } else {
         // This applies to the first line after a header.
@@ -169,3 +457,126 @@ fn parse_text_line(current_block: &mut Block, raw_line: RawLine) -> FormattedLin
         FormattedLine::from_raw(raw_line, current_block.contents_indent_level())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finalize_fenced_block_falls_back_to_dedent_on_indenter_length_mismatch() {
+        struct BrokenIndenter;
+
+        impl FencedCodeIndenter for BrokenIndenter {
+            fn filetype(&self) -> &str {
+                "broken"
+            }
+
+            fn reindent(&self, _code: &str) -> Option<Vec<String>> {
+                Some(vec!["only one line".to_string()])
+            }
+        }
+
+        let buffered_lines = vec![
+            ("one".to_string(), RawLine::from_string("one")),
+            ("two".to_string(), RawLine::from_string("two")),
+        ];
+        let indenter: &dyn FencedCodeIndenter = &BrokenIndenter;
+
+        let lines = finalize_fenced_block(
+            buffered_lines,
+            Some("broken".to_string()),
+            &[indenter],
+            DEFAULT_TAB_WIDTH,
+            0,
+        );
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].contents, "one");
+        assert_eq!(lines[1].contents, "two");
+    }
+
+    #[test]
+    fn render_indent_renders_tabs_and_spaces_per_level() {
+        assert_eq!(render_indent(0, IndentStyle::Tabs), "");
+        assert_eq!(render_indent(1, IndentStyle::Tabs), "\t");
+        assert_eq!(render_indent(2, IndentStyle::Tabs), "\t\t");
+
+        assert_eq!(render_indent(0, IndentStyle::Spaces(2)), "");
+        assert_eq!(render_indent(1, IndentStyle::Spaces(2)), "  ");
+        assert_eq!(render_indent(2, IndentStyle::Spaces(2)), "    ");
+    }
+
+    #[test]
+    fn formatted_line_render_prefixes_contents_with_its_indent() {
+        let line = FormattedLine::from_raw(RawLine::from_string("text"), 2);
+
+        assert_eq!(line.render(IndentStyle::Spaces(2)), "    text");
+        assert_eq!(line.render(IndentStyle::Tabs), "\t\ttext");
+    }
+
+    #[test]
+    fn marker_filetype_strips_trailing_annotations() {
+        assert_eq!(
+            marker_filetype(&RawLine::from_string("```rust,no_run")),
+            Some("rust".to_string())
+        );
+        assert_eq!(
+            marker_filetype(&RawLine::from_string("```rust ignore")),
+            Some("rust".to_string())
+        );
+        assert_eq!(
+            marker_filetype(&RawLine::from_string("```rust")),
+            Some("rust".to_string())
+        );
+    }
+
+    #[test]
+    fn detect_indent_style_breaks_ties_towards_the_widest_candidate() {
+        // The indent grows by exactly 2, then 4, then 8 (dropping back to 0 in between), so each
+        // candidate width gets one vote and all three tie.
+        let contents = "a\n  b\nc\n    d\ne\n        f\n";
+
+        let style = detect_indent_style(contents);
+
+        assert_eq!(style, IndentStyle::Spaces(8));
+    }
+
+    #[test]
+    fn detect_indent_style_falls_back_to_four_spaces_when_nothing_grows() {
+        let style = detect_indent_style("a\nb\nc\n");
+
+        assert_eq!(style, IndentStyle::Spaces(4));
+    }
+
+    #[test]
+    fn dedent_fenced_block_ignores_a_bare_first_line_when_computing_the_minimum() {
+        let buffered_lines = vec![
+            ("code".to_string(), RawLine::from_string("code")),
+            ("    nested".to_string(), RawLine::from_string("    nested")),
+        ];
+
+        let lines = dedent_fenced_block(buffered_lines, DEFAULT_TAB_WIDTH, 0);
+
+        assert_eq!(lines[0].contents, "code");
+        assert_eq!(lines[1].contents, "nested");
+    }
+
+    #[test]
+    fn parse_text_line_continuation_copies_the_previous_rendered_indent_level() {
+        // A header followed by two lines of a wrapped paragraph with no leading whitespace: the
+        // second line must align with the first, not snap back to the block's base indent.
+        let first_indent = parse_document("# Header\nfirst line\n")
+            .last_block()
+            .last_line()
+            .unwrap()
+            .indent_level;
+
+        let second_indent = parse_document("# Header\nfirst line\nsecond line\n")
+            .last_block()
+            .last_line()
+            .unwrap()
+            .indent_level;
+
+        assert_eq!(first_indent, second_indent);
+    }
+}